@@ -1,4 +1,4 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::json;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 use strum::Display;
@@ -25,28 +25,28 @@ pub enum SubscribeParam {
     Alarm(AlarmParam),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct InterfaceChangeParam {
-    name: String,
+    pub(crate) name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct BlockUpdateParam {
-    pos: BlockPos,
+    pub pos: BlockPos,
     #[serde(rename = "type")]
-    type_: BlockUpdateType,
+    pub type_: BlockUpdateType,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct AlarmParam {
-    gametime: i64,
-    at: AlarmAt,
+    pub gametime: i64,
+    pub at: AlarmAt,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct BlockPos(i32, i32, i32, String);
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockPos(pub i32, pub i32, pub i32, pub String);
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum BlockUpdateType {
     NeighborUpdate,
@@ -54,7 +54,7 @@ pub enum BlockUpdateType {
     Any,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum AlarmAt {
     Start,
@@ -139,10 +139,19 @@ pub struct ScriptRunContent {
     pub(crate) argument: Vec<serde_json::Value>,
 }
 
+impl ScriptRunContent {
+    /// Deserializes the positional arguments into `T`, returning
+    /// `ErrorCode::ArgumentInvalid` if the shape or arity doesn't match.
+    pub fn parse<T: DeserializeOwned>(&self) -> std::result::Result<T, ErrorCode> {
+        serde_json::from_value(serde_json::Value::Array(self.argument.clone()))
+            .map_err(|_| ErrorCode::ArgumentInvalid)
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InterfaceChangeContent {
-    pub(crate) previous: String,
-    pub(crate) current: String,
+    pub previous: String,
+    pub current: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -1,13 +1,25 @@
 mod datatype;
 
+use std::collections::VecDeque;
 use std::sync::Arc;
+use std::time::Duration;
 
-use futures::{FutureExt, SinkExt, TryStreamExt, future::BoxFuture};
+use futures::{
+    FutureExt, SinkExt, StreamExt, TryStreamExt,
+    future::BoxFuture,
+    stream::{SplitSink, SplitStream},
+};
 use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
 use serde::de::DeserializeOwned;
 pub use serde_json;
 use serde_json::Value;
-use tokio::{net::TcpStream, select, signal::ctrl_c, sync::Mutex};
+use tokio::{
+    net::TcpStream,
+    select,
+    signal::ctrl_c,
+    sync::{Mutex, Notify, mpsc, oneshot},
+    task::JoinHandle,
+};
 use tokio_tungstenite::{
     MaybeTlsStream, WebSocketStream, connect_async,
     tungstenite::{self, Message},
@@ -29,6 +41,8 @@ pub enum Error {
     UnexpectedDisconnect,
     #[error("scriptInitialize callback returned Err")]
     InitializeFailed,
+    #[error("request timed out")]
+    Timeout,
     #[error("failed to serialize message")]
     SerializeFailed(#[from] serde_json::Error),
     #[error("error code from server")]
@@ -38,16 +52,90 @@ pub enum Error {
 pub type Result<T> = std::result::Result<T, Error>;
 
 trait_set! {
-    trait OnInitCallback = FnOnce(Context) -> BoxFuture<'static, std::result::Result<(), ErrorCode>>;
+    trait OnInitCallback = Fn(Context) -> BoxFuture<'static, std::result::Result<(), ErrorCode>>;
     trait OnExecuteCallback = Fn(Context, Vec<serde_json::Value>) -> BoxFuture<'static, std::result::Result<i32, ErrorCode>>;
+    trait OnInterfaceChangeCallback = Fn(Context, InterfaceChangeContent) -> BoxFuture<'static, std::result::Result<(), ErrorCode>>;
+    trait OnBlockUpdateCallback = Fn(Context, BlockUpdateParam) -> BoxFuture<'static, std::result::Result<(), ErrorCode>>;
+    trait OnAlarmCallback = Fn(Context, AlarmParam) -> BoxFuture<'static, std::result::Result<(), ErrorCode>>;
+    trait OnConnectionChangeCallback = Fn(Context, ConnectionState) -> BoxFuture<'static, ()>;
+}
+
+/// Exponential backoff with full jitter, used to pace reconnect attempts.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+        }
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, multiplier: f64) -> Self {
+        Self {
+            initial,
+            max,
+            multiplier,
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let capped = (self.initial.as_secs_f64() * self.multiplier.powi(attempt as i32))
+            .min(self.max.as_secs_f64());
+        Duration::from_secs_f64(capped * jitter_fraction())
+    }
+}
+
+/// A pseudo-random value in `[0, 1)` for full-jitter backoff, without pulling
+/// in a `rand` dependency for a single call site: mixes the current time
+/// through a splitmix64 step.
+fn jitter_fraction() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connected,
+    Disconnected,
+    Reconnecting,
 }
 
 pub struct Script {
     name: String,
     description: String,
     server: String,
-    on_init: Option<Box<dyn OnInitCallback + Send>>,
+    on_init: Option<Arc<dyn OnInitCallback + Send + Sync>>,
     on_execute: Option<Arc<dyn OnExecuteCallback + Send + Sync>>,
+    on_interface_change: Vec<(
+        InterfaceChangeParam,
+        Arc<dyn OnInterfaceChangeCallback + Send + Sync>,
+    )>,
+    on_block_update: Vec<(
+        BlockUpdateParam,
+        Arc<dyn OnBlockUpdateCallback + Send + Sync>,
+    )>,
+    on_alarm: Vec<(AlarmParam, Arc<dyn OnAlarmCallback + Send + Sync>)>,
+    on_connection_change: Option<Arc<dyn OnConnectionChangeCallback + Send + Sync>>,
+    request_timeout: Duration,
+    reconnect: Option<Backoff>,
+    shutdown: Arc<Notify>,
 }
 
 impl Default for Script {
@@ -58,10 +146,32 @@ impl Default for Script {
             server: "ws://localhost:37265/".to_owned(),
             on_init: None,
             on_execute: None,
+            on_interface_change: Vec::new(),
+            on_block_update: Vec::new(),
+            on_alarm: Vec::new(),
+            on_connection_change: None,
+            request_timeout: Duration::from_secs(30),
+            reconnect: None,
+            shutdown: Arc::new(Notify::new()),
         }
     }
 }
 
+/// Lets an embedder stop a running [`Script`] from outside its main loop —
+/// another task, a timer, or a test.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle(Arc<Notify>);
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        // `notify_one` stores a permit when nothing is currently waiting, so a
+        // call made while the main loop is busy (e.g. inside `handle_event`)
+        // is still picked up by the next `notified()` it registers. Unlike
+        // `notify_waiters`, it isn't lost if no one is waiting yet.
+        self.0.notify_one();
+    }
+}
+
 impl Script {
     pub fn new(name: impl AsRef<str>) -> Self {
         Self::default().name(name)
@@ -82,12 +192,26 @@ impl Script {
         self
     }
 
+    pub fn request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
+
+    pub fn reconnect(mut self, backoff: Backoff) -> Self {
+        self.reconnect = Some(backoff);
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.shutdown.clone())
+    }
+
     pub fn on_init<F, Fut>(mut self, callback: F) -> Self
     where
-        F: FnOnce(Context) -> Fut + Send + 'static,
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
         Fut: Future<Output = std::result::Result<(), ErrorCode>> + Send + 'static,
     {
-        self.on_init = Some(Box::new(move |ctx| callback(ctx).boxed()));
+        self.on_init = Some(Arc::new(move |ctx| callback(ctx).boxed()));
         self
     }
 
@@ -100,101 +224,379 @@ impl Script {
         self
     }
 
-    pub async fn run(self) -> Result<()> {
-        info!("Connecting to server");
-        let url = format!(
-            "{}?name={}&description={}",
-            self.server,
-            url_encode_query(&self.name),
-            url_encode_query(&self.description)
-        );
-        let (ws, _) = connect_async(url).await?;
-        let ws = Arc::new(Mutex::new(ws));
-        Context {
-            script: Arc::new(Mutex::new(self)),
-            ws: ws.clone(),
-        }
-        .main_loop()
-        .await?;
-        let mut lock = ws.lock().await;
-        lock.close(None).await?;
-        lock.flush().await?;
-        Ok(())
+    pub fn on_execute_typed<T, F, Fut>(self, callback: F) -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(Context, T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<i32, ErrorCode>> + Send + 'static,
+    {
+        let callback = Arc::new(callback);
+        self.on_execute(move |ctx, args| {
+            let callback = callback.clone();
+            async move {
+                let arg = ScriptRunContent { argument: args }.parse()?;
+                callback(ctx, arg).await
+            }
+        })
     }
-}
 
-#[derive(Clone)]
-pub struct Context {
-    script: Arc<Mutex<Script>>,
-    ws: Arc<Mutex<WebSocketStream<MaybeTlsStream<TcpStream>>>>,
-}
+    pub fn on_interface_change<F, Fut>(mut self, name: impl AsRef<str>, callback: F) -> Self
+    where
+        F: Fn(Context, InterfaceChangeContent) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<(), ErrorCode>> + Send + 'static,
+    {
+        self.on_interface_change.push((
+            InterfaceChangeParam {
+                name: name.as_ref().to_owned(),
+            },
+            Arc::new(move |ctx, content| callback(ctx, content).boxed()),
+        ));
+        self
+    }
 
-impl Context {
-    async fn main_loop(&mut self) -> Result<()> {
-        info!("Connected");
+    pub fn on_block_update<F, Fut>(
+        mut self,
+        pos: BlockPos,
+        type_: BlockUpdateType,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(Context, BlockUpdateParam) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<(), ErrorCode>> + Send + 'static,
+    {
+        self.on_block_update.push((
+            BlockUpdateParam { pos, type_ },
+            Arc::new(move |ctx, param| callback(ctx, param).boxed()),
+        ));
+        self
+    }
+
+    pub fn on_alarm<F, Fut>(mut self, gametime: i64, at: AlarmAt, callback: F) -> Self
+    where
+        F: Fn(Context, AlarmParam) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::result::Result<(), ErrorCode>> + Send + 'static,
+    {
+        self.on_alarm.push((
+            AlarmParam { gametime, at },
+            Arc::new(move |ctx, param| callback(ctx, param).boxed()),
+        ));
+        self
+    }
+
+    pub fn on_connection_change<F, Fut>(mut self, callback: F) -> Self
+    where
+        F: Fn(Context, ConnectionState) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        self.on_connection_change = Some(Arc::new(move |ctx, state| callback(ctx, state).boxed()));
+        self
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let reconnect = self.reconnect;
+        let script = Arc::new(Mutex::new(self));
+        let mut ctx = connect(script.clone()).await?;
         loop {
-            let Some(evt) = self.try_next_event().await? else {
+            ctx.notify_connection_change(ConnectionState::Connected)
+                .await;
+            if let LoopExit::Shutdown = ctx.main_loop().await? {
                 return Ok(());
+            }
+            ctx.notify_connection_change(ConnectionState::Disconnected)
+                .await;
+            let Some(backoff) = reconnect else {
+                return Err(Error::UnexpectedDisconnect);
             };
-            Box::pin(self.handle_event(evt)).await?;
+            match reconnect_with_backoff(script.clone(), backoff, &ctx).await {
+                ReconnectOutcome::Reconnected(new_ctx) => ctx = new_ctx,
+                ReconnectOutcome::Shutdown => return Ok(()),
+            }
         }
     }
+}
+
+async fn connect(script: Arc<Mutex<Script>>) -> Result<Context> {
+    let (url, request_timeout, shutdown) = {
+        let script = script.lock().await;
+        (
+            format!(
+                "{}?name={}&description={}",
+                script.server,
+                url_encode_query(&script.name),
+                url_encode_query(&script.description)
+            ),
+            script.request_timeout,
+            script.shutdown.clone(),
+        )
+    };
+    info!("Connecting to server");
+    let (ws, _) = connect_async(url).await?;
+    let (sink, stream) = ws.split();
+    let (write_tx, write_rx) = mpsc::unbounded_channel();
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(writer_task(sink, write_rx));
+    let transport = Arc::new(Transport {
+        write_tx,
+        pending: Mutex::new(VecDeque::new()),
+        request_timeout,
+        writer: Mutex::new(Some(writer)),
+    });
+    tokio::spawn(reader_task(stream, transport.clone(), event_tx));
+    Ok(Context {
+        script,
+        transport,
+        events: Arc::new(Mutex::new(event_rx)),
+        shutdown,
+    })
+}
 
-    async fn try_next_event(&mut self) -> Result<Option<Event>> {
-        match self.try_next_event_or_api_result().await? {
-            Some(EventOrApiResult::Event(evt)) => Ok(Some(evt)),
-            Some(EventOrApiResult::ApiResult(res)) => {
-                error!("unexpected api result {res:?}");
-                Err(Error::UnexpectedApiResult)
+enum ReconnectOutcome {
+    Reconnected(Context),
+    Shutdown,
+}
+
+/// Retries `connect` with exponential backoff until it succeeds. The server
+/// re-sends `ScriptInitialize` on every new connection, so the existing
+/// `ScriptInitialize` handler re-subscribes everything once `main_loop` picks
+/// it up on the returned `Context` — there's nothing extra to replay here.
+///
+/// Both the backoff sleep and the connection attempt race `ctrl_c()` and the
+/// script's `shutdown` notify, so `ShutdownHandle::shutdown()` (or Ctrl-C)
+/// still works while the server is unreachable instead of being swallowed
+/// until it comes back.
+async fn reconnect_with_backoff(
+    script: Arc<Mutex<Script>>,
+    backoff: Backoff,
+    ctx: &Context,
+) -> ReconnectOutcome {
+    let mut notifier = ctx.clone();
+    let mut attempt = 0u32;
+    loop {
+        notifier
+            .notify_connection_change(ConnectionState::Reconnecting)
+            .await;
+        let delay = backoff.delay(attempt);
+        info!("Reconnecting in {delay:?}");
+        select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = ctrl_c() => {
+                info!("Shutdown");
+                return ReconnectOutcome::Shutdown;
+            }
+            _ = ctx.shutdown.notified() => {
+                info!("Shutdown");
+                return ReconnectOutcome::Shutdown;
             }
-            None => Ok(None),
         }
+        select! {
+            result = connect(script.clone()) => match result {
+                Ok(ctx) => return ReconnectOutcome::Reconnected(ctx),
+                Err(e) => {
+                    error!("reconnect attempt failed: {e}");
+                    attempt += 1;
+                }
+            },
+            _ = ctrl_c() => {
+                info!("Shutdown");
+                return ReconnectOutcome::Shutdown;
+            }
+            _ = ctx.shutdown.notified() => {
+                info!("Shutdown");
+                return ReconnectOutcome::Shutdown;
+            }
+        }
+    }
+}
+
+// The server answers requests in the order they were sent, so `pending` only
+// ever needs to match the front of the queue against the next `ApiResult`.
+struct Transport {
+    write_tx: mpsc::UnboundedSender<Message>,
+    pending: Mutex<VecDeque<oneshot::Sender<std::result::Result<Value, ErrorCode>>>>,
+    request_timeout: Duration,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Transport {
+    async fn send_request(
+        &self,
+        req: &ApiRequest,
+    ) -> Result<oneshot::Receiver<std::result::Result<Value, ErrorCode>>> {
+        let message = serde_json::to_string(req)?;
+        let (tx, rx) = oneshot::channel();
+        // Hold the lock across the push and the send so two concurrent callers
+        // can't interleave push order with wire order (which would hand a
+        // reply to the wrong waiter).
+        let mut pending = self.pending.lock().await;
+        pending.push_back(tx);
+        self.send_message(Message::Text(message.into()))?;
+        drop(pending);
+        Ok(rx)
     }
 
-    async fn try_next_event_or_api_result(&mut self) -> Result<Option<EventOrApiResult>> {
-        let Some(s) = self.try_next_message().await? else {
-            return Ok(None);
+    fn send_message(&self, message: Message) -> Result<()> {
+        self.write_tx
+            .send(message)
+            .map_err(|_| Error::UnexpectedDisconnect)
+    }
+
+    /// Queues a Close frame and waits for the writer task to actually send
+    /// and flush it (and close the socket) before returning, instead of
+    /// dropping the transport while the frame is still only queued.
+    async fn close(&self) -> Result<()> {
+        self.send_message(Message::Close(None))?;
+        let writer = self.writer.lock().await.take();
+        if let Some(writer) = writer {
+            let _ = writer.await;
+        }
+        Ok(())
+    }
+}
+
+async fn reader_task(
+    mut stream: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    transport: Arc<Transport>,
+    event_tx: mpsc::UnboundedSender<Event>,
+) {
+    loop {
+        let message = match stream.try_next().await {
+            Ok(Some(message)) => message,
+            Ok(None) => break,
+            Err(e) => {
+                error!("websocket error: {e}");
+                break;
+            }
+        };
+        let text = match message {
+            Message::Text(bytes) => bytes.to_string(),
+            Message::Binary(b) => {
+                error!("unrecognized server message {b:?}");
+                continue;
+            }
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
         };
-        if let Ok(evt) = serde_json::from_str::<Event>(&s) {
-            trace!("event request {evt:?}");
-            return Ok(Some(EventOrApiResult::Event(evt)));
+        if let Ok(evt) = serde_json::from_str::<Event>(&text) {
+            trace!("event {evt:?}");
+            if event_tx.send(evt).is_err() {
+                break;
+            }
+            continue;
         }
-        if let Ok(res) = serde_json::from_str::<ApiResultWrapper>(&s) {
+        if let Ok(res) = serde_json::from_str::<ApiResultWrapper>(&text) {
             trace!("api result {res:?}");
-            return Ok(Some(EventOrApiResult::ApiResult(res)));
+            let Some(sender) = transport.pending.lock().await.pop_front() else {
+                error!("{}", Error::UnexpectedApiResult);
+                continue;
+            };
+            let _ = sender.send(res.result.into_result());
+            continue;
         }
-        error!("unrecognized server message {s:?}");
-        Err(Error::InvalidServerMessage)
+        error!("{}: {text:?}", Error::InvalidServerMessage);
+        break;
     }
+    // The connection is gone; wake up anyone still waiting for a reply instead
+    // of leaving their request hanging forever.
+    transport.pending.lock().await.clear();
+}
+
+async fn writer_task(
+    mut sink: SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>,
+    mut write_rx: mpsc::UnboundedReceiver<Message>,
+) {
+    while let Some(message) = write_rx.recv().await {
+        // `SinkExt::send` flushes the frame itself, so a queued Close is
+        // actually on the wire by the time this task exits and `Transport::close`
+        // stops waiting on it.
+        let is_close = matches!(message, Message::Close(_));
+        if let Err(e) = sink.send(message).await {
+            error!("websocket error: {e}");
+            break;
+        }
+        if is_close {
+            break;
+        }
+    }
+    if let Err(e) = sink.close().await {
+        error!("websocket error: {e}");
+    }
+}
+
+#[derive(Clone)]
+pub struct Context {
+    script: Arc<Mutex<Script>>,
+    transport: Arc<Transport>,
+    events: Arc<Mutex<mpsc::UnboundedReceiver<Event>>>,
+    shutdown: Arc<Notify>,
+}
+
+enum LoopExit {
+    Shutdown,
+    Disconnected,
+}
+
+enum NextEvent {
+    Event(Event),
+    Shutdown,
+    Disconnected,
+}
 
-    async fn try_next_message(&mut self) -> Result<Option<String>> {
+/// Whether a `BlockUpdate` subscription should fire for an incoming event,
+/// keyed by position with `BlockUpdateType::Any` acting as a wildcard type.
+fn block_update_matches(sub: &BlockUpdateParam, evt: &BlockUpdateParam) -> bool {
+    sub.pos == evt.pos && (sub.type_ == BlockUpdateType::Any || sub.type_ == evt.type_)
+}
+
+/// Whether an `Alarm` subscription should fire for an incoming event, keyed
+/// by `(gametime, at)` so a `Start` and an `End` alarm at the same gametime
+/// don't shadow each other.
+fn alarm_matches(sub: &AlarmParam, evt: &AlarmParam) -> bool {
+    sub.gametime == evt.gametime && sub.at == evt.at
+}
+
+impl Context {
+    async fn main_loop(&mut self) -> Result<LoopExit> {
+        info!("Connected");
         loop {
-            let Some(message) = ({
-                let mut lock = self.ws.lock().await;
-                select! {
-                    message = lock.try_next() => {
-                        message?
-                    }
-                    _ = ctrl_c() => {
-                        info!("Shutdown");
-                        return Ok(None);
-                    }
+            match self.try_next_event().await? {
+                NextEvent::Event(evt) => Box::pin(self.handle_event(evt)).await?,
+                NextEvent::Shutdown => {
+                    // Wait for the writer task to actually send and flush the
+                    // Close frame before returning, instead of dropping the
+                    // transport while it's still only queued.
+                    self.transport.close().await?;
+                    return Ok(LoopExit::Shutdown);
                 }
-            }) else {
-                return Ok(None);
-            };
-            match message {
-                Message::Text(bytes) => return Ok(Some(bytes.to_string())),
-                Message::Binary(b) => {
-                    error!("unrecognized server message {b:?}");
-                    return Err(Error::InvalidServerMessage);
-                }
-                Message::Close(_) => return Ok(None),
-                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                NextEvent::Disconnected => return Ok(LoopExit::Disconnected),
             }
         }
     }
 
+    async fn try_next_event(&mut self) -> Result<NextEvent> {
+        let mut events = self.events.lock().await;
+        select! {
+            evt = events.recv() => Ok(match evt {
+                Some(evt) => NextEvent::Event(evt),
+                None => NextEvent::Disconnected,
+            }),
+            _ = ctrl_c() => {
+                info!("Shutdown");
+                Ok(NextEvent::Shutdown)
+            }
+            _ = self.shutdown.notified() => {
+                info!("Shutdown");
+                Ok(NextEvent::Shutdown)
+            }
+        }
+    }
+
+    async fn notify_connection_change(&mut self, state: ConnectionState) {
+        let callback = { self.script.lock().await.on_connection_change.clone() };
+        if let Some(callback) = callback {
+            callback(self.clone(), state).await;
+        }
+    }
+
     async fn handle_event(&mut self, evt: Event) -> Result<()> {
         match evt {
             Event::ScriptInitialize {} => {
@@ -202,7 +604,33 @@ impl Context {
                 if self.script.lock().await.on_execute.is_some() {
                     self.subscribe_run().await?;
                 }
-                if let Some(callback) = { self.script.lock().await.on_init.take() } {
+                let subscriptions = {
+                    let script = self.script.lock().await;
+                    script
+                        .on_interface_change
+                        .iter()
+                        .map(|(param, _)| SubscribeParam::InterfaceChange(param.clone()))
+                        .chain(
+                            script
+                                .on_block_update
+                                .iter()
+                                .map(|(param, _)| SubscribeParam::BlockUpdate(param.clone())),
+                        )
+                        .chain(
+                            script
+                                .on_alarm
+                                .iter()
+                                .map(|(param, _)| SubscribeParam::Alarm(param.clone())),
+                        )
+                        .collect::<Vec<_>>()
+                };
+                for param in subscriptions {
+                    self.subscribe(param).await?;
+                }
+                // `clone`d rather than `take`n: `ScriptInitialize` fires again on
+                // every reconnect, and a script that sets up interface state in
+                // `on_init` expects that setup to run again too.
+                if let Some(callback) = { self.script.lock().await.on_init.clone() } {
                     debug!("Call on_init");
                     let result = callback(self.clone()).await;
                     if let Err(e) = result {
@@ -231,10 +659,63 @@ impl Context {
                 self.send_event_response(EventResponse::ScriptRun { result })
                     .await?;
             }
-            // TODO: impl
-            Event::InterfaceChange { .. } => {}
-            Event::BlockUpdate { .. } => {}
-            Event::Alarm { .. } => {}
+            Event::InterfaceChange { param, content } => {
+                let callback = {
+                    self.script
+                        .lock()
+                        .await
+                        .on_interface_change
+                        .iter()
+                        .find(|(p, _)| *p == param)
+                        .map(|(_, callback)| callback.clone())
+                };
+                if let Some(callback) = callback {
+                    debug!("Call on_interface_change");
+                    if let Err(e) = callback(self.clone(), content).await {
+                        self.send_event_response(EventResponse::Err(e)).await?;
+                        return Ok(());
+                    }
+                }
+                self.send_event_response(EventResponse::empty()).await?;
+            }
+            Event::BlockUpdate { param } => {
+                let callback = {
+                    self.script
+                        .lock()
+                        .await
+                        .on_block_update
+                        .iter()
+                        .find(|(p, _)| block_update_matches(p, &param))
+                        .map(|(_, callback)| callback.clone())
+                };
+                if let Some(callback) = callback {
+                    debug!("Call on_block_update");
+                    if let Err(e) = callback(self.clone(), param).await {
+                        self.send_event_response(EventResponse::Err(e)).await?;
+                        return Ok(());
+                    }
+                }
+                self.send_event_response(EventResponse::empty()).await?;
+            }
+            Event::Alarm { param } => {
+                let callback = {
+                    self.script
+                        .lock()
+                        .await
+                        .on_alarm
+                        .iter()
+                        .find(|(p, _)| alarm_matches(p, &param))
+                        .map(|(_, callback)| callback.clone())
+                };
+                if let Some(callback) = callback {
+                    debug!("Call on_alarm");
+                    if let Err(e) = callback(self.clone(), param).await {
+                        self.send_event_response(EventResponse::Err(e)).await?;
+                        return Ok(());
+                    }
+                }
+                self.send_event_response(EventResponse::empty()).await?;
+            }
         }
         Ok(())
     }
@@ -250,17 +731,15 @@ impl Context {
 
     async fn send_api_request<T: DeserializeOwned>(&mut self, req: ApiRequest) -> Result<T> {
         trace!("api request {req:?}");
-        self.send(serde_json::to_string(&req)?).await?;
-        loop {
-            match self.try_next_event_or_api_result().await? {
-                Some(EventOrApiResult::ApiResult(res)) => {
-                    let value = res.result.into_result()?;
-                    return Ok(serde_json::from_value(value)?);
-                }
-                Some(EventOrApiResult::Event(evt)) => Box::pin(self.handle_event(evt)).await?,
-                None => return Err(Error::UnexpectedDisconnect),
-            }
-        }
+        let rx = self.transport.send_request(&req).await?;
+        // On timeout `rx` is dropped here but its sender stays queued in `pending`,
+        // so the reply it was waiting for (if it ever arrives) is discarded instead
+        // of being mis-delivered to whichever request is next in line.
+        let value = tokio::time::timeout(self.transport.request_timeout, rx)
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(|_| Error::UnexpectedDisconnect)??;
+        Ok(serde_json::from_value(value)?)
     }
 
     async fn send_api_request_discard_result(&mut self, req: ApiRequest) -> Result<()> {
@@ -269,16 +748,18 @@ impl Context {
     }
 
     async fn send(&mut self, message: String) -> Result<()> {
-        let mut lock = self.ws.lock().await;
-        lock.send(Message::Text(message.into())).await?;
-        Ok(())
+        self.transport.send_message(Message::Text(message.into()))
     }
 
-    pub async fn subscribe_run(&mut self) -> Result<()> {
-        self.send_api_request_discard_result(ApiRequest::Subscribe(SubscribeParam::ScriptRun {}))
+    async fn subscribe(&mut self, param: SubscribeParam) -> Result<()> {
+        self.send_api_request_discard_result(ApiRequest::Subscribe(param))
             .await
     }
 
+    pub async fn subscribe_run(&mut self) -> Result<()> {
+        self.subscribe(SubscribeParam::ScriptRun {}).await
+    }
+
     pub async fn read_interface(&mut self, name: impl AsRef<str>) -> Result<String> {
         let result: ReadInterfaceResult = self
             .send_api_request(ApiRequest::ReadInterface {
@@ -350,8 +831,123 @@ fn url_encode_query(s: &str) -> String {
     utf8_percent_encode(s, QUERY).to_string()
 }
 
-#[derive(Debug, Clone)]
-pub(crate) enum EventOrApiResult {
-    Event(Event),
-    ApiResult(ApiResultWrapper),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn backoff_delay_is_never_negative_and_never_exceeds_the_capped_max() {
+        let backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(5), 2.0);
+        for attempt in 0..10 {
+            let capped = (backoff.initial.as_secs_f64() * backoff.multiplier.powi(attempt as i32))
+                .min(backoff.max.as_secs_f64());
+            let delay = backoff.delay(attempt).as_secs_f64();
+            assert!((0.0..=capped).contains(&delay), "delay {delay} not in [0, {capped}]");
+        }
+    }
+
+    #[test]
+    fn backoff_delay_is_capped_by_max_even_for_large_attempts() {
+        let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(2), 10.0);
+        let delay = backoff.delay(5).as_secs_f64();
+        assert!(delay <= backoff.max.as_secs_f64());
+    }
+
+    #[test]
+    fn script_run_content_parse_accepts_matching_shape() {
+        let content = ScriptRunContent {
+            argument: vec![json!(1), json!("hello")],
+        };
+        let (n, s): (i32, String) = content.parse().unwrap();
+        assert_eq!(n, 1);
+        assert_eq!(s, "hello");
+    }
+
+    #[test]
+    fn script_run_content_parse_rejects_wrong_arity() {
+        let content = ScriptRunContent {
+            argument: vec![json!(1)],
+        };
+        let result: std::result::Result<(i32, i32), ErrorCode> = content.parse();
+        assert_eq!(result.unwrap_err(), ErrorCode::ArgumentInvalid);
+    }
+
+    #[test]
+    fn script_run_content_parse_rejects_wrong_element_type() {
+        let content = ScriptRunContent {
+            argument: vec![json!("not a number")],
+        };
+        let result: std::result::Result<(i32,), ErrorCode> = content.parse();
+        assert_eq!(result.unwrap_err(), ErrorCode::ArgumentInvalid);
+    }
+
+    fn block_pos(x: i32) -> BlockPos {
+        BlockPos(x, 0, 0, "minecraft:overworld".to_owned())
+    }
+
+    #[test]
+    fn block_update_any_subscription_matches_any_type_at_the_same_pos() {
+        let sub = BlockUpdateParam {
+            pos: block_pos(0),
+            type_: BlockUpdateType::Any,
+        };
+        let evt = BlockUpdateParam {
+            pos: block_pos(0),
+            type_: BlockUpdateType::NeighborUpdate,
+        };
+        assert!(block_update_matches(&sub, &evt));
+    }
+
+    #[test]
+    fn block_update_specific_subscription_does_not_match_a_different_type() {
+        let sub = BlockUpdateParam {
+            pos: block_pos(0),
+            type_: BlockUpdateType::PostPlacement,
+        };
+        let evt = BlockUpdateParam {
+            pos: block_pos(0),
+            type_: BlockUpdateType::NeighborUpdate,
+        };
+        assert!(!block_update_matches(&sub, &evt));
+    }
+
+    #[test]
+    fn block_update_does_not_match_a_different_pos() {
+        let sub = BlockUpdateParam {
+            pos: block_pos(0),
+            type_: BlockUpdateType::Any,
+        };
+        let evt = BlockUpdateParam {
+            pos: block_pos(1),
+            type_: BlockUpdateType::Any,
+        };
+        assert!(!block_update_matches(&sub, &evt));
+    }
+
+    #[test]
+    fn alarm_matches_same_gametime_and_at() {
+        let sub = AlarmParam {
+            gametime: 100,
+            at: AlarmAt::Start,
+        };
+        let evt = AlarmParam {
+            gametime: 100,
+            at: AlarmAt::Start,
+        };
+        assert!(alarm_matches(&sub, &evt));
+    }
+
+    #[test]
+    fn alarm_does_not_match_a_different_at_at_the_same_gametime() {
+        let sub = AlarmParam {
+            gametime: 100,
+            at: AlarmAt::Start,
+        };
+        let evt = AlarmParam {
+            gametime: 100,
+            at: AlarmAt::End,
+        };
+        assert!(!alarm_matches(&sub, &evt));
+    }
 }